@@ -28,6 +28,8 @@ enum Color {
     Rgb(u8, u8, u8),
     // Hex color support (converted to RGB internally)
     Hex(String),
+    // 256-color palette index (38;5;N / 48;5;N)
+    Ansi256(u8),
 }
 
 #[allow(unused)]
@@ -59,6 +61,8 @@ impl Color {
                 let (r, g, b) = Self::hex_to_rgb(hex);
                 format!("38;2;{};{};{}", r, g, b)
             }
+            // 256-color palette index
+            Color::Ansi256(n) => format!("38;5;{}", n),
         }
     }
 
@@ -89,6 +93,8 @@ impl Color {
                 let (r, g, b) = Self::hex_to_rgb(hex);
                 format!("48;2;{};{};{}", r, g, b)
             }
+            // 256-color palette index
+            Color::Ansi256(n) => format!("48;5;{}", n),
         }
     }
 
@@ -124,6 +130,10 @@ enum DecoratedString {
     Background(Box<DecoratedString>, Color),
     Underlined(Box<DecoratedString>),
     Italic(Box<DecoratedString>),
+    Dim(Box<DecoratedString>),
+    Reverse(Box<DecoratedString>),
+    Strikethrough(Box<DecoratedString>),
+    Concat(Vec<DecoratedString>),
     Default(String),
 }
 
@@ -157,6 +167,26 @@ impl DecoratedString {
                 Self::append_to_ansi(inner, s, escape_fn)?;
                 write!(s, "{}", escape_fn("\x1b[23m"))?;
             }
+            DecoratedString::Dim(inner) => {
+                write!(s, "{}", escape_fn("\x1b[2m"))?;
+                Self::append_to_ansi(inner, s, escape_fn)?;
+                write!(s, "{}", escape_fn("\x1b[22m"))?;
+            }
+            DecoratedString::Reverse(inner) => {
+                write!(s, "{}", escape_fn("\x1b[7m"))?;
+                Self::append_to_ansi(inner, s, escape_fn)?;
+                write!(s, "{}", escape_fn("\x1b[27m"))?;
+            }
+            DecoratedString::Strikethrough(inner) => {
+                write!(s, "{}", escape_fn("\x1b[9m"))?;
+                Self::append_to_ansi(inner, s, escape_fn)?;
+                write!(s, "{}", escape_fn("\x1b[29m"))?;
+            }
+            DecoratedString::Concat(parts) => {
+                for part in parts {
+                    Self::append_to_ansi(part, s, escape_fn)?;
+                }
+            }
             DecoratedString::Default(val) => {
                 write!(s, "{val}")?;
             }
@@ -191,9 +221,43 @@ impl DecoratedString {
         DecoratedString::Italic(Box::new(self))
     }
 
+    fn dim(self) -> DecoratedString {
+        DecoratedString::Dim(Box::new(self))
+    }
+
+    fn reverse(self) -> DecoratedString {
+        DecoratedString::Reverse(Box::new(self))
+    }
+
+    fn strikethrough(self) -> DecoratedString {
+        DecoratedString::Strikethrough(Box::new(self))
+    }
+
+    fn concat(parts: Vec<DecoratedString>) -> DecoratedString {
+        DecoratedString::Concat(parts)
+    }
+
     fn new(s: String) -> DecoratedString {
         DecoratedString::Default(s)
     }
+
+    /// The undecorated text, with every style wrapper stripped. Used by tests to
+    /// assert on the rendered content without threading ANSI escapes through.
+    #[cfg(test)]
+    fn plain(&self) -> String {
+        match self {
+            DecoratedString::Bold(inner)
+            | DecoratedString::Colored(inner, _)
+            | DecoratedString::Background(inner, _)
+            | DecoratedString::Underlined(inner)
+            | DecoratedString::Italic(inner)
+            | DecoratedString::Dim(inner)
+            | DecoratedString::Reverse(inner)
+            | DecoratedString::Strikethrough(inner) => inner.plain(),
+            DecoratedString::Concat(parts) => parts.iter().map(|p| p.plain()).collect(),
+            DecoratedString::Default(val) => val.clone(),
+        }
+    }
 }
 
 // #[allow(unused)]
@@ -270,20 +334,307 @@ impl DecoratedString {
 //     }
 // }
 
-fn get_cwd() -> DecoratedString {
-    let cwd = env::var("PWD");
+/// A resolved text style: an optional foreground and background color plus a
+/// set of attributes. Maps directly onto the `DecoratedString` decorators.
+#[derive(Debug, Clone, Default)]
+struct Style {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    reverse: bool,
+    strike: bool,
+}
+
+/// A single color token in Git's color grammar. `Default` resets to the
+/// terminal default (i.e. no explicit color).
+enum ColorSpec {
+    Color(Color),
+    Default,
+}
+
+impl Style {
+    /// Parse a Git-style color value: a space-separated list of up to two
+    /// colors (foreground then background) followed by attributes. See
+    /// `git help config` (the `color` type) for the grammar; the accepted
+    /// subset is named colors and their `bright*` forms, `default`, decimal
+    /// palette indices `0`–`255`, `#rrggbb` hex, and the attributes `bold`,
+    /// `dim`, `italic`, `ul`/`underline`, `reverse`, `strike` (each clearable
+    /// with a `no-`/`no` prefix).
+    fn parse(input: &str) -> Style {
+        let mut style = Style::default();
+        let mut colors_seen = 0;
+
+        for token in input.split_whitespace() {
+            let lower = token.to_ascii_lowercase();
+
+            if let Some(rest) = lower.strip_prefix("no-") {
+                if apply_attr(&mut style, rest, false) {
+                    continue;
+                }
+            }
+            if let Some(rest) = lower.strip_prefix("no") {
+                if apply_attr(&mut style, rest, false) {
+                    continue;
+                }
+            }
+            if apply_attr(&mut style, &lower, true) {
+                continue;
+            }
+
+            if let Some(spec) = parse_color(&lower) {
+                let color = match spec {
+                    ColorSpec::Color(c) => Some(c),
+                    ColorSpec::Default => None,
+                };
+                match colors_seen {
+                    0 => style.fg = color,
+                    1 => style.bg = color,
+                    _ => {}
+                }
+                colors_seen += 1;
+            }
+        }
+
+        style
+    }
+
+    fn apply(&self, mut s: DecoratedString) -> DecoratedString {
+        if let Some(color) = &self.fg {
+            s = s.colored(color.clone());
+        }
+        if let Some(color) = &self.bg {
+            s = s.background(color.clone());
+        }
+        if self.bold {
+            s = s.bold();
+        }
+        if self.dim {
+            s = s.dim();
+        }
+        if self.italic {
+            s = s.italic();
+        }
+        if self.underline {
+            s = s.underlined();
+        }
+        if self.reverse {
+            s = s.reverse();
+        }
+        if self.strike {
+            s = s.strikethrough();
+        }
+        s
+    }
+}
+
+fn apply_attr(style: &mut Style, name: &str, value: bool) -> bool {
+    match name {
+        "bold" => style.bold = value,
+        "dim" => style.dim = value,
+        "italic" => style.italic = value,
+        "ul" | "underline" => style.underline = value,
+        "reverse" => style.reverse = value,
+        "strike" => style.strike = value,
+        _ => return false,
+    }
+    true
+}
+
+fn parse_color(token: &str) -> Option<ColorSpec> {
+    let color = match token {
+        "default" => return Some(ColorSpec::Default),
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "white" => Color::White,
+        "brightblack" => Color::BrightBlack,
+        "brightred" => Color::BrightRed,
+        "brightgreen" => Color::BrightGreen,
+        "brightyellow" => Color::BrightYellow,
+        "brightblue" => Color::BrightBlue,
+        "brightmagenta" => Color::BrightMagenta,
+        "brightcyan" => Color::BrightCyan,
+        "brightwhite" => Color::BrightWhite,
+        _ => {
+            if let Some(hex) = token.strip_prefix('#') {
+                if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Some(ColorSpec::Color(Color::Hex(token.to_string())));
+                }
+                return None;
+            }
+            // A bare number is a palette index.
+            return token
+                .parse::<u8>()
+                .ok()
+                .map(|n| ColorSpec::Color(Color::Ansi256(n)));
+        }
+    };
+    Some(ColorSpec::Color(color))
+}
+
+/// Per-segment styles, loaded from `~/.config/prompt/config.toml` and falling
+/// back to the built-in defaults for any absent file or key.
+#[derive(Debug, Clone)]
+struct Config {
+    cwd: Style,
+    git: Style,
+    nix: Style,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cwd: Style {
+                fg: Some(Color::White),
+                bold: true,
+                ..Style::default()
+            },
+            git: Style {
+                fg: Some(Color::Hex("#98BFAE".to_string())),
+                bold: true,
+                ..Style::default()
+            },
+            nix: Style {
+                fg: Some(Color::Hex("#E06C76".to_string())),
+                bold: true,
+                ..Style::default()
+            },
+        }
+    }
+}
+
+impl Config {
+    fn load(ctx: &Context) -> Config {
+        let mut config = Config::default();
+
+        let home = match ctx.var("HOME") {
+            Some(home) => home,
+            None => return config,
+        };
+        let path = std::path::Path::new(&home)
+            .join(".config")
+            .join("prompt")
+            .join("config.toml");
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return config,
+        };
+
+        // A deliberately small TOML subset: a `[styles]` table of
+        // `key = "value"` entries, one per segment.
+        let mut in_styles = false;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_styles = line == "[styles]";
+                continue;
+            }
+            if !in_styles {
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once('=') {
+                let value = value.trim().trim_matches('"');
+                let style = Style::parse(value);
+                match key.trim() {
+                    "cwd" => config.cwd = style,
+                    "git" => config.git = style,
+                    "nix" => config.nix = style,
+                    _ => {}
+                }
+            }
+        }
+
+        config
+    }
+}
+
+/// The shell we are rendering the prompt for, as declared by
+/// `PROMPT_SHELL_TYPE`. Determines how escape sequences are wrapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShellType {
+    Zsh,
+    Bash,
+    Unknown,
+}
+
+impl ShellType {
+    fn from_var(value: Option<&str>) -> ShellType {
+        match value {
+            Some("zsh") => ShellType::Zsh,
+            Some("bash") => ShellType::Bash,
+            _ => ShellType::Unknown,
+        }
+    }
+}
+
+/// Owns the process environment the prompt reads from so that every segment can
+/// be exercised deterministically in tests. The real constructor snapshots the
+/// process; the test constructor takes explicit overrides.
+struct Context {
+    vars: std::collections::HashMap<String, String>,
+    current_dir: std::path::PathBuf,
+    shell_type: ShellType,
+}
+
+impl Context {
+    fn new() -> Context {
+        let vars: std::collections::HashMap<String, String> = env::vars().collect();
+        let current_dir = env::current_dir().unwrap_or_default();
+        let shell_type = ShellType::from_var(vars.get("PROMPT_SHELL_TYPE").map(|s| s.as_str()));
+
+        Context {
+            vars,
+            current_dir,
+            shell_type,
+        }
+    }
+
+    #[cfg(test)]
+    fn test(
+        vars: std::collections::HashMap<String, String>,
+        current_dir: std::path::PathBuf,
+        shell_type: ShellType,
+    ) -> Context {
+        Context {
+            vars,
+            current_dir,
+            shell_type,
+        }
+    }
+
+    fn var(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(|s| s.as_str())
+    }
 
-    if cwd.is_err() {
-        return DecoratedString::new("!!!".to_string())
-            .bold()
-            .colored(Color::Red);
+    fn current_dir(&self) -> &std::path::Path {
+        &self.current_dir
     }
+}
 
-    let mut cwd = cwd.unwrap();
+fn get_cwd(ctx: &Context, config: &Config) -> DecoratedString {
+    let mut cwd = match ctx.var("PWD") {
+        Some(cwd) => cwd.to_string(),
+        None => {
+            return DecoratedString::new("!!!".to_string())
+                .bold()
+                .colored(Color::Red);
+        }
+    };
 
-    if let Ok(home) = env::var("HOME") {
-        if cwd.starts_with(&home) {
-            cwd = cwd.replace(&home, "~");
+    if let Some(home) = ctx.var("HOME") {
+        if cwd.starts_with(home) {
+            cwd = cwd.replace(home, "~");
         }
     }
 
@@ -298,9 +649,7 @@ fn get_cwd() -> DecoratedString {
         }
     }).collect::<Vec<String>>().join("/");
 
-    DecoratedString::new(shortened_cwd)
-        .bold()
-        .colored(Color::White)
+    config.cwd.apply(DecoratedString::new(shortened_cwd))
 }
 
 #[derive(Debug)]
@@ -318,76 +667,98 @@ impl fmt::Display for NotInNixShell {
 enum NixShellType {
     Pure,
     Impure,
+    /// A `nix develop`/flake dev shell. These don't set `IN_NIX_SHELL` but do
+    /// expose a `/nix/store` `PATH` together with the derivation's `name`.
+    Develop,
     /// We're in a Nix shell, but we don't know which type.
     /// This can only happen in a `nix shell` shell (not a `nix-shell` one).
     Unknown,
 }
 
 impl NixShellType {
-    fn detect_shell_type() -> Result<Self, NotInNixShell> {
-        use NixShellType::{Impure, Pure, Unknown};
-
-        let shell_type = env::var("IN_NIX_SHELL");
-        match shell_type {
-            Ok(val) if val == "pure" => return Ok(Pure),
-            Ok(val) if val == "impure" => return Ok(Impure),
-            Ok(_) => return Ok(Unknown),
-            _ => {},
+    fn detect_shell_type(ctx: &Context) -> Result<Self, NotInNixShell> {
+        use NixShellType::{Develop, Impure, Pure, Unknown};
+
+        match ctx.var("IN_NIX_SHELL") {
+            Some("pure") => return Ok(Pure),
+            Some("impure") => return Ok(Impure),
+            Some(_) => return Ok(Unknown),
+            None => {}
         }
 
-        // Hack to detect if we're in a `nix shell`
-        let path = env::var("PATH").map_err(|_| NotInNixShell)?;
-        let in_nix_shell = env::split_paths(&path)
+        // Hack to detect if we're in a `nix shell` / `nix develop`
+        let path = ctx.var("PATH").ok_or(NotInNixShell)?;
+        let in_nix_shell = env::split_paths(path)
             .any(|p: std::path::PathBuf| p.starts_with("/nix/store"));
 
-        if in_nix_shell {
-            Ok(Unknown)
+        if !in_nix_shell {
+            return Err(NotInNixShell);
+        }
+
+        // A `nix develop`/flake dev shell exports the derivation's build
+        // environment (`NIX_BUILD_TOP`, `buildInputs`, `stdenv`, …). Gate on one
+        // of those Nix-specific markers rather than the generic `name` variable,
+        // which is present in plenty of ordinary shells and would misclassify
+        // any Nix-on-the-system user as being in a dev shell.
+        let in_dev_shell = ctx.var("NIX_BUILD_TOP").is_some()
+            || ctx.var("buildInputs").is_some()
+            || ctx.var("stdenv").is_some();
+        if in_dev_shell {
+            Ok(Develop)
         } else {
-            Err(NotInNixShell)
+            Ok(Unknown)
         }
     }
 }
 
-fn get_nix_shell() -> Result<DecoratedString, NotInNixShell> {
-    use NixShellType::{Impure, Pure, Unknown};
+fn get_nix_shell(ctx: &Context, config: &Config) -> Result<DecoratedString, NotInNixShell> {
+    use NixShellType::{Develop, Impure, Pure, Unknown};
 
-    let shell_type = NixShellType::detect_shell_type()?;
+    let shell_type = NixShellType::detect_shell_type(ctx)?;
 
-    let name = match shell_type {
+    let label = match shell_type {
         Pure => "pure",
         Impure => "impure",
+        Develop => "develop",
         Unknown => "unknown",
     };
 
-    Ok(DecoratedString::new(format!("(nix: {})", name))
-        .bold()
-        .colored(Color::Hex("#E06C76".to_string())))
+    // The derivation name (set by `nix-shell`/`nix develop`). An empty value is
+    // rendered as `?` so the segment still signals that a name was expected.
+    let name = match ctx.var("name") {
+        Some(name) if !name.is_empty() => Some(name.to_string()),
+        Some(_) => Some("?".to_string()),
+        None => None,
+    };
+
+    let text = match name {
+        Some(name) => format!("(nix: {label} · {name})"),
+        None => format!("(nix: {label})"),
+    };
+
+    Ok(config.nix.apply(DecoratedString::new(text)))
         // Or this blue #61AFF0
         // Or this red? #F14E32
 }
 
 #[derive(Debug)]
 enum GitError {
-    NoCwd(std::io::Error),
     CanonicalCwd(std::io::Error),
     ReadGitFile(std::io::Error),
     ReadHead(std::io::Error),
     NotGitRepo,
     UnexpectedGitContent,
-    ReadRef(std::io::Error),
     NoRefName,
 }
 
 impl fmt::Display for GitError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            GitError::NoCwd(_) => write!(f, "failed to get cwd"),
             GitError::CanonicalCwd(_) => write!(f, "failed to canonicalize cwd"),
             GitError::ReadGitFile(_) => write!(f, "failed to read .git file"),
             GitError::ReadHead(_) => write!(f, "failed to read git HEAD"),
             GitError::NotGitRepo => write!(f, "not a git repo"),
             GitError::UnexpectedGitContent => write!(f, "unexpected git content"),
-            GitError::ReadRef(_) => write!(f, "failed to read ref"),
             GitError::NoRefName => write!(f, "failed to get ref name"),
         }
     }
@@ -396,26 +767,23 @@ impl fmt::Display for GitError {
 impl Error for GitError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            GitError::NoCwd(e) => Some(e),
             GitError::CanonicalCwd(e) => Some(e),
             GitError::ReadGitFile(e) => Some(e),
             GitError::ReadHead(e) => Some(e),
             GitError::NotGitRepo => None,
             GitError::UnexpectedGitContent => None,
-            GitError::ReadRef(e) => Some(e),
             GitError::NoRefName => None,
         }
     }
 }
 
-fn get_git_info() -> Result<DecoratedString, GitError> {
+fn get_git_info(ctx: &Context, config: &Config) -> Result<DecoratedString, GitError> {
     use std::{
         fs,
         path::*,
     };
 
-    let cwd = env::current_dir().map_err(GitError::NoCwd)?;
-    let canonical_cwd = fs::canonicalize(cwd).map_err(GitError::CanonicalCwd)?;
+    let canonical_cwd = fs::canonicalize(ctx.current_dir()).map_err(GitError::CanonicalCwd)?;
 
     let mut dir_iter = Some(&canonical_cwd as &Path);
     while let Some(dir) = dir_iter {
@@ -443,120 +811,1690 @@ fn get_git_info() -> Result<DecoratedString, GitError> {
     }
 
     let head_content = fs::read_to_string(git_dir.join("HEAD")).map_err(GitError::ReadHead)?;
-
-    const REF_PREFIX: &str = "ref: ";
-    let output = match head_content.strip_prefix(REF_PREFIX) {
-        Some(refs_path) => {
-            let refs_path = Path::new(refs_path.trim());
-
-            let commit_hash =
-                fs::read_to_string(git_dir.join(refs_path)).map_err(GitError::ReadRef)?;
-
-            let short_hash = &commit_hash[..5];
-            let ref_name = refs_path
+    let head_content = head_content.trim();
+
+    let output = if let Some(refs_path) = head_content.strip_prefix("ref: ") {
+        // Symbolic ref, i.e. a branch. Resolve it through the loose ref file
+        // and, failing that, `packed-refs`. A branch with no commits yet
+        // resolves to nothing, in which case we show the bare name.
+        let refs_path = refs_path.trim();
+        // Show the full branch name, so `refs/heads/feature/foo` displays as
+        // `feature/foo` rather than just `foo`.
+        let ref_name = match refs_path.strip_prefix("refs/heads/") {
+            Some(branch) => branch.to_string(),
+            None => Path::new(refs_path)
                 .file_name()
                 .ok_or(GitError::NoRefName)?
-                .to_string_lossy();
-
-            let extension = if commit_hash.chars().count() > 5 {
-                ".."
-            } else {
-                ""
-            };
+                .to_string_lossy()
+                .into_owned(),
+        };
 
-            format!("({ref_name} {short_hash}{extension})")
+        match resolve_ref(&git_dir, refs_path) {
+            Some(hash) => format!("({ref_name} {})", short_hash(&hash)),
+            None => format!("({ref_name})"),
         }
-        None => head_content[..14].to_string(),
+    } else if is_object_id(head_content) {
+        // Detached HEAD pointing straight at an object id. Prefer a tag name
+        // if one points at this commit, otherwise show the truncated id.
+        match tag_for_commit(&git_dir, head_content) {
+            Some(tag) => format!("({tag} {})", short_hash(head_content)),
+            None => format!("(HEAD@{})", short_hash(head_content)),
+        }
+    } else {
+        return Err(GitError::UnexpectedGitContent);
     };
 
-    Ok(DecoratedString::new(output)
-        .bold()
-        .colored(Color::Hex("#98BFAE".to_string())))
+    let branch = config.git.apply(DecoratedString::new(output));
         // Or this pink #FFAFD2
+
+    // The branch component is followed by a compact working-tree status
+    // segment. A failure to read the status should never hide the branch, so
+    // we degrade to the bare branch and only surface the error under
+    // `PROMPT_DEBUG`.
+    match get_git_status(&git_dir, repo) {
+        Ok(status) => match status.render() {
+            Some(status) => Ok(DecoratedString::concat(vec![
+                branch,
+                DecoratedString::new(" ".to_string()),
+                status,
+            ])),
+            None => Ok(branch),
+        },
+        Err(e) => {
+            if ctx.var("PROMPT_DEBUG") == Some("1") {
+                eprintln!("failed to get git status\nCaused by:\n{e}");
+            }
+            Ok(branch)
+        }
+    }
 }
 
 #[derive(Debug)]
-enum MainError {
-    NixShell(NotInNixShell),
-    Git(GitError),
+enum GitStatusError {
+    ReadIndex(std::io::Error),
+    UnexpectedIndexContent,
+    WalkTree(std::io::Error),
 }
 
-impl fmt::Display for MainError {
+impl fmt::Display for GitStatusError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let source: &dyn std::error::Error = match self {
-            MainError::NixShell(e) => {
-                writeln!(f, "failed to get nix info")?;
-                e
-            },
-            MainError::Git(e) => {
-                writeln!(f, "failed to get git info")?;
-                e
-            },
-        };
+        match self {
+            GitStatusError::ReadIndex(_) => write!(f, "failed to read .git/index"),
+            GitStatusError::UnexpectedIndexContent => write!(f, "unexpected .git/index content"),
+            GitStatusError::WalkTree(_) => write!(f, "failed to walk working tree"),
+        }
+    }
+}
 
-        writeln!(f, "Caused by:")?;
+impl Error for GitStatusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GitStatusError::ReadIndex(e) => Some(e),
+            GitStatusError::UnexpectedIndexContent => None,
+            GitStatusError::WalkTree(e) => Some(e),
+        }
+    }
+}
 
-        let mut source = Some(source);
-        while let Some(err) = source {
-            writeln!(f, "{err}")?;
-            source = err.source();
+/// Counts describing the state of the working tree relative to the index and
+/// the upstream branch. `ahead`/`behind` are optional because resolving them
+/// requires reading commit objects (loose or packed), which can fail for an
+/// unsupported index/pack format.
+#[derive(Debug, Default)]
+struct GitStatus {
+    staged: usize,
+    modified: usize,
+    untracked: usize,
+    conflicted: usize,
+    ahead: Option<usize>,
+    behind: Option<usize>,
+}
+
+impl GitStatus {
+    /// Render the non-empty counts as a row of independently colored symbols
+    /// (`+2 ~1 !0 ?3 ⇡1⇣0`). Returns `None` when there is nothing to show so
+    /// the caller can emit the bare branch.
+    fn render(&self) -> Option<DecoratedString> {
+        let mut parts: Vec<DecoratedString> = Vec::new();
+
+        if self.staged > 0 {
+            parts.push(DecoratedString::new(format!("+{}", self.staged)).colored(Color::Green));
+        }
+        if self.modified > 0 {
+            parts.push(DecoratedString::new(format!("~{}", self.modified)).colored(Color::Yellow));
+        }
+        if self.conflicted > 0 {
+            parts.push(DecoratedString::new(format!("!{}", self.conflicted)).colored(Color::Red));
+        }
+        if self.untracked > 0 {
+            parts.push(DecoratedString::new(format!("?{}", self.untracked)).colored(Color::Blue));
+        }
+        if let (Some(ahead), Some(behind)) = (self.ahead, self.behind) {
+            if ahead > 0 || behind > 0 {
+                parts.push(
+                    DecoratedString::new(format!("⇡{ahead}⇣{behind}")).colored(Color::Cyan),
+                );
+            }
         }
 
-        Ok(())
+        if parts.is_empty() {
+            return None;
+        }
+
+        let mut joined = Vec::with_capacity(parts.len() * 2);
+        for (i, part) in parts.into_iter().enumerate() {
+            if i > 0 {
+                joined.push(DecoratedString::new(" ".to_string()));
+            }
+            joined.push(part);
+        }
+
+        Some(DecoratedString::concat(joined))
     }
 }
 
-fn main() {
-    // This program uses these environment variables:
-    //
-    // 1. `PROMPT_DEBUG`:
-    //      1 => Print out debug stats
-    //      0 => No debug
-    // 2. `PROMPT_SHELL_TYPE`:
-    //      'bash' => The current shell is bash
-    //      'zsh' => The current shell is zsh
-    //
-    // Here is how to setup the prompt for zsh:
-    // ```.zshrc
-    // PROMPT="$(PROMPT_SHELL_TYPE='zsh' ./path/to/prompt/binary)"
-    // ```
+/// A single parsed `.git/index` (DIRC) entry. We only keep the fields needed to
+/// detect modifications and merge conflicts.
+struct IndexEntry {
+    path: String,
+    mtime_s: u32,
+    size: u32,
+    sha: [u8; 20],
+    stage: u8,
+}
 
-    let shell_type = env::var("PROMPT_SHELL_TYPE").expect("Prompt shell type is unspecified");
+fn be32(data: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]])
+}
 
-    let escape_ansi = |s: &str| -> String {
-        if shell_type == "zsh" {
-            format!("%{{{s}%}}")
-        } else if shell_type == "bash" {
-            format!("\\[{s}\\]")
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parse the `.git/index` in the DIRC v2/v3 format: a 12-byte header
+/// (`DIRC`, version, entry count) followed by fixed-length entries, each
+/// NUL-terminated and padded so its length is a multiple of eight. The v4
+/// prefix-compressed format is not supported and reports as unexpected content.
+fn read_index(git_dir: &std::path::Path) -> Result<Vec<IndexEntry>, GitStatusError> {
+    use std::fs;
+
+    let data = fs::read(git_dir.join("index")).map_err(GitStatusError::ReadIndex)?;
+    if data.len() < 12 || &data[0..4] != b"DIRC" {
+        return Err(GitStatusError::UnexpectedIndexContent);
+    }
+
+    let version = be32(&data, 4);
+    if version == 4 {
+        // Prefix-compressed entries have no fixed layout; bail rather than
+        // misparse.
+        return Err(GitStatusError::UnexpectedIndexContent);
+    }
+
+    let count = be32(&data, 8) as usize;
+    let mut entries = Vec::with_capacity(count);
+    let mut off = 12;
+
+    for _ in 0..count {
+        if off + 62 > data.len() {
+            return Err(GitStatusError::UnexpectedIndexContent);
+        }
+
+        let mtime_s = be32(&data, off + 8);
+        let size = be32(&data, off + 36);
+        let mut sha = [0u8; 20];
+        sha.copy_from_slice(&data[off + 40..off + 60]);
+        let flags = u16::from_be_bytes([data[off + 60], data[off + 61]]);
+        let stage = ((flags >> 12) & 0x3) as u8;
+        let extended = flags & 0x4000 != 0;
+
+        let fixed = if extended { 64 } else { 62 };
+        let name_start = off + fixed;
+        if name_start > data.len() {
+            return Err(GitStatusError::UnexpectedIndexContent);
+        }
+
+        let declared = (flags & 0x0fff) as usize;
+        let name_len = if declared < 0xfff {
+            if name_start + declared > data.len() {
+                return Err(GitStatusError::UnexpectedIndexContent);
+            }
+            declared
         } else {
-            s.to_string()
+            let mut end = name_start;
+            while end < data.len() && data[end] != 0 {
+                end += 1;
+            }
+            end - name_start
+        };
+
+        let path = String::from_utf8_lossy(&data[name_start..name_start + name_len]).into_owned();
+        let entry_len = (fixed + name_len + 8) & !7;
+
+        entries.push(IndexEntry {
+            path,
+            mtime_s,
+            size,
+            sha,
+            stage,
+        });
+
+        off += entry_len;
+    }
+
+    Ok(entries)
+}
+
+/// Read an object by id, returning its type (`commit`, `tree`, …) and raw body
+/// with any header stripped. Loose objects are tried first, then the packfiles
+/// under `objects/pack`. Returns `None` only when the object is genuinely
+/// missing (or stored in a format we can't parse), so status derivation still
+/// degrades gracefully.
+fn read_object(git_dir: &std::path::Path, sha: &str) -> Option<(String, Vec<u8>)> {
+    read_loose_object(git_dir, sha).or_else(|| read_packed_object(git_dir, sha))
+}
+
+/// Read and inflate a loose object, stripping the `"<type> <size>\0"` header.
+fn read_loose_object(git_dir: &std::path::Path, sha: &str) -> Option<(String, Vec<u8>)> {
+    use std::fs;
+
+    if sha.len() < 3 {
+        return None;
+    }
+
+    let path = git_dir
+        .join("objects")
+        .join(&sha[0..2])
+        .join(&sha[2..]);
+    let raw = fs::read(path).ok()?;
+    let data = inflate(&raw)?;
+
+    let nul = data.iter().position(|&b| b == 0)?;
+    let header = std::str::from_utf8(&data[..nul]).ok()?;
+    let kind = header.split(' ').next()?.to_string();
+
+    Some((kind, data[nul + 1..].to_vec()))
+}
+
+/// Map a packfile object type number to the string Git uses elsewhere.
+fn pack_type_name(kind: u8) -> Option<&'static str> {
+    match kind {
+        1 => Some("commit"),
+        2 => Some("tree"),
+        3 => Some("blob"),
+        4 => Some("tag"),
+        _ => None,
+    }
+}
+
+/// Decode a 40/64-hex object id to its raw bytes.
+fn object_id_bytes(sha: &str) -> Option<Vec<u8>> {
+    if !sha.len().is_multiple_of(2) || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..sha.len() / 2)
+        .map(|i| u8::from_str_radix(&sha[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+/// Look up an object across every `*.idx` under `objects/pack` and, when found,
+/// read it out of the companion `.pack` (resolving OFS/REF deltas). Only the v2
+/// index format is understood; v1 indexes are skipped rather than misparsed.
+fn read_packed_object(git_dir: &std::path::Path, sha: &str) -> Option<(String, Vec<u8>)> {
+    use std::fs;
+
+    let wanted = object_id_bytes(sha)?;
+    let pack_dir = git_dir.join("objects").join("pack");
+
+    for entry in fs::read_dir(&pack_dir).ok()?.flatten() {
+        let idx_path = entry.path();
+        if idx_path.extension().and_then(|e| e.to_str()) != Some("idx") {
+            continue;
+        }
+
+        let idx = match fs::read(&idx_path) {
+            Ok(idx) => idx,
+            Err(_) => continue,
+        };
+        let Some(offset) = idx_find_offset(&idx, &wanted) else {
+            continue;
+        };
+
+        let pack = match fs::read(idx_path.with_extension("pack")) {
+            Ok(pack) => pack,
+            Err(_) => continue,
+        };
+        if let Some((kind, body)) = read_pack_entry(git_dir, &pack, offset) {
+            return Some((pack_type_name(kind)?.to_string(), body));
         }
+    }
+
+    None
+}
+
+/// Find `sha`'s byte offset into the pack using a v2 `.idx` (magic `\377tOc`):
+/// a 256-entry fanout table, the sorted id table, CRCs, then 4-byte offsets
+/// with an overflow table for offsets past 2 GiB.
+fn idx_find_offset(idx: &[u8], sha: &[u8]) -> Option<u64> {
+    const HEADER: usize = 8;
+    const FANOUT: usize = 256 * 4;
+
+    if idx.len() < HEADER + FANOUT || idx[0..4] != [0xff, b't', b'O', b'c'] {
+        return None;
+    }
+    if be32(idx, 4) != 2 {
+        return None;
+    }
+
+    let total = be32(idx, HEADER + 255 * 4) as usize;
+    let first = sha[0] as usize;
+    let mut lo = if first == 0 {
+        0
+    } else {
+        be32(idx, HEADER + (first - 1) * 4) as usize
     };
+    let mut hi = be32(idx, HEADER + first * 4) as usize;
 
-    let (oks, errors): (Vec<Result<_, MainError>>, Vec<_>) = vec![
-        Ok(get_cwd()),
-        get_git_info().map_err(MainError::Git),
-        get_nix_shell().map_err(MainError::NixShell),
-    ]
-    .into_iter()
-    .partition(Result::is_ok);
+    let names = HEADER + FANOUT;
+    if names + total * 20 > idx.len() {
+        return None;
+    }
 
-    let components: Vec<_> = oks
-        .into_iter()
-        .map(|x| x.expect("Invalid Result"))
-        .collect();
+    let mut found = None;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let at = names + mid * 20;
+        match idx[at..at + 20].cmp(sha) {
+            std::cmp::Ordering::Less => lo = mid + 1,
+            std::cmp::Ordering::Greater => hi = mid,
+            std::cmp::Ordering::Equal => {
+                found = Some(mid);
+                break;
+            }
+        }
+    }
+    let i = found?;
+
+    let offsets = names + total * 20 + total * 4;
+    if offsets + (i + 1) * 4 > idx.len() {
+        return None;
+    }
+    let raw = be32(idx, offsets + i * 4);
+    if raw & 0x8000_0000 == 0 {
+        return Some(raw as u64);
+    }
+
+    let large = offsets + total * 4 + (raw & 0x7fff_ffff) as usize * 8;
+    if large + 8 > idx.len() {
+        return None;
+    }
+    let bytes: [u8; 8] = idx[large..large + 8].try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
 
-    if Ok("1") == env::var("PROMPT_DEBUG").as_ref().map(|s| s.as_str()) {
-        for error in errors.into_iter().map(|e| e.unwrap_err()) {
-            eprintln!("{error}");
+/// Read the object stored at `offset` in a pack, reconstructing delta-encoded
+/// objects against their base (by in-pack offset for OFS_DELTA, by id for
+/// REF_DELTA). Returns the resolved `(type, body)`.
+fn read_pack_entry(
+    git_dir: &std::path::Path,
+    pack: &[u8],
+    offset: u64,
+) -> Option<(u8, Vec<u8>)> {
+    let mut pos = offset as usize;
+    let mut byte = *pack.get(pos)?;
+    pos += 1;
+
+    let kind = (byte >> 4) & 0x7;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = *pack.get(pos)?;
+        pos += 1;
+        shift += 7;
+    }
+    let _ = shift; // size is implied by the inflated length; we don't need it
+
+    match kind {
+        1..=4 => Some((kind, inflate(pack.get(pos..)?)?)),
+        6 => {
+            let mut byte = *pack.get(pos)?;
+            pos += 1;
+            let mut base_delta = (byte & 0x7f) as u64;
+            while byte & 0x80 != 0 {
+                byte = *pack.get(pos)?;
+                pos += 1;
+                base_delta = ((base_delta + 1) << 7) | (byte & 0x7f) as u64;
+            }
+            let base_offset = offset.checked_sub(base_delta)?;
+            let (base_kind, base) = read_pack_entry(git_dir, pack, base_offset)?;
+            let delta = inflate(pack.get(pos..)?)?;
+            Some((base_kind, apply_delta(&base, &delta)?))
+        }
+        7 => {
+            let base_sha = hex(pack.get(pos..pos + 20)?);
+            pos += 20;
+            let (base_name, base) = read_object(git_dir, &base_sha)?;
+            let base_kind = match base_name.as_str() {
+                "commit" => 1,
+                "tree" => 2,
+                "blob" => 3,
+                "tag" => 4,
+                _ => return None,
+            };
+            let delta = inflate(pack.get(pos..)?)?;
+            Some((base_kind, apply_delta(&base, &delta)?))
         }
+        _ => None,
     }
+}
 
-    let joined = components
-        .into_iter()
-        .map(|s| format!("{} ", s.to_ansi(&escape_ansi)))
-        .collect::<Vec<_>>()
-        .join("");
+/// Apply a Git delta (`src-size`, `dst-size` varints followed by copy/insert
+/// instructions) to `base`, producing the target object.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    let (_src, used) = delta_varint(delta, pos)?;
+    pos += used;
+    let (dst, used) = delta_varint(delta, pos)?;
+    pos += used;
+
+    let mut out = Vec::with_capacity(dst);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            let mut copy_off = 0usize;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    copy_off |= (*delta.get(pos)? as usize) << (8 * i);
+                    pos += 1;
+                }
+            }
+            let mut copy_len = 0usize;
+            for i in 0..3 {
+                if op & (1 << (4 + i)) != 0 {
+                    copy_len |= (*delta.get(pos)? as usize) << (8 * i);
+                    pos += 1;
+                }
+            }
+            if copy_len == 0 {
+                copy_len = 0x10000;
+            }
+            let end = copy_off.checked_add(copy_len)?;
+            out.extend_from_slice(base.get(copy_off..end)?);
+        } else if op != 0 {
+            let len = op as usize;
+            out.extend_from_slice(delta.get(pos..pos + len)?);
+            pos += len;
+        } else {
+            return None;
+        }
+    }
+
+    if out.len() != dst {
+        return None;
+    }
+    Some(out)
+}
+
+/// Read a little-endian base-128 varint (as used in delta headers), returning
+/// the value and the number of bytes consumed.
+fn delta_varint(data: &[u8], start: usize) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    let mut i = 0;
+    loop {
+        let byte = *data.get(start + i)?;
+        i += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    Some((value, i))
+}
+
+fn resolve_ref(git_dir: &std::path::Path, refname: &str) -> Option<String> {
+    use std::fs;
+
+    if let Ok(s) = fs::read_to_string(git_dir.join(refname)) {
+        if let Some(token) = s.split_whitespace().next() {
+            if is_object_id(token) {
+                return Some(token.to_string());
+            }
+        }
+    }
+
+    let packed = fs::read_to_string(git_dir.join("packed-refs")).ok()?;
+    for line in packed.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        if let Some((sha, name)) = line.split_once(' ') {
+            if name.trim() == refname {
+                return Some(sha.trim().to_string());
+            }
+        }
+    }
+
+    None
+}
+
+/// A hex object id: 40 characters for SHA-1 repos, 64 for SHA-256.
+fn is_object_id(s: &str) -> bool {
+    (s.len() == 40 || s.len() == 64) && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Truncate an object id to five characters, appending `..` when it was longer.
+/// Works on char boundaries so it never panics on short or non-hex content.
+fn short_hash(hash: &str) -> String {
+    let hash = hash.trim();
+    let short: String = hash.chars().take(5).collect();
+    if hash.chars().count() > 5 {
+        format!("{short}..")
+    } else {
+        short
+    }
+}
+
+/// Peel an annotated tag object to the object it points at.
+fn peel_tag(git_dir: &std::path::Path, sha: &str) -> Option<String> {
+    let (kind, body) = read_object(git_dir, sha)?;
+    if kind != "tag" {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&body);
+    text.lines()
+        .find_map(|l| l.strip_prefix("object "))
+        .map(|s| s.trim().to_string())
+}
+
+/// Find a tag (lightweight or annotated) pointing at `commit`, checking
+/// `packed-refs` (using its `^peeled` annotations) then the loose
+/// `refs/tags` directory.
+fn tag_for_commit(git_dir: &std::path::Path, commit: &str) -> Option<String> {
+    use std::fs;
+
+    if let Ok(packed) = fs::read_to_string(git_dir.join("packed-refs")) {
+        let mut last_tag: Option<String> = None;
+        for line in packed.lines() {
+            if let Some(peeled) = line.strip_prefix('^') {
+                if peeled.trim() == commit {
+                    if let Some(tag) = &last_tag {
+                        return Some(tag.clone());
+                    }
+                }
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((sha, name)) = line.split_once(' ') {
+                if let Some(tag) = name.trim().strip_prefix("refs/tags/") {
+                    if sha.trim() == commit {
+                        return Some(tag.to_string());
+                    }
+                    last_tag = Some(tag.to_string());
+                } else {
+                    last_tag = None;
+                }
+            }
+        }
+    }
+
+    let tags_dir = git_dir.join("refs").join("tags");
+    if let Ok(entries) = fs::read_dir(&tags_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            if let Ok(content) = fs::read_to_string(&path) {
+                let sha = content.trim();
+                let points_at =
+                    sha == commit || peel_tag(git_dir, sha).as_deref() == Some(commit);
+                if points_at {
+                    return Some(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn resolve_head_sha(git_dir: &std::path::Path) -> Option<String> {
+    use std::fs;
+
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+
+    if let Some(refname) = head.strip_prefix("ref: ") {
+        resolve_ref(git_dir, refname.trim())
+    } else if is_object_id(head) {
+        Some(head.to_string())
+    } else {
+        None
+    }
+}
+
+fn current_branch(git_dir: &std::path::Path) -> Option<String> {
+    use std::fs;
+
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    head.trim()
+        .strip_prefix("ref: refs/heads/")
+        .map(|s| s.trim().to_string())
+}
+
+/// Resolve the SHA the current branch's upstream points at, following the
+/// `[branch "name"]` section of `.git/config` to a `refs/remotes/<remote>/…`
+/// (or local, for `remote = .`) ref.
+fn upstream_sha(git_dir: &std::path::Path) -> Option<String> {
+    use std::fs;
+
+    let branch = current_branch(git_dir)?;
+    let config = fs::read_to_string(git_dir.join("config")).ok()?;
+
+    let header = format!("[branch \"{branch}\"]");
+    let mut in_section = false;
+    let mut remote = None;
+    let mut merge = None;
+
+    for line in config.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_section = line == header;
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("remote") {
+            if let Some((_, val)) = rest.split_once('=') {
+                remote = Some(val.trim().to_string());
+            }
+        } else if let Some(rest) = line.strip_prefix("merge") {
+            if let Some((_, val)) = rest.split_once('=') {
+                merge = Some(val.trim().to_string());
+            }
+        }
+    }
+
+    let remote = remote?;
+    let merge = merge?;
+    let short = merge.strip_prefix("refs/heads/").unwrap_or(&merge);
+
+    let upstream = if remote == "." {
+        merge.clone()
+    } else {
+        format!("refs/remotes/{remote}/{short}")
+    };
+
+    resolve_ref(git_dir, &upstream)
+}
+
+fn commit_parents(git_dir: &std::path::Path, sha: &str) -> Option<Vec<String>> {
+    let (kind, body) = read_object(git_dir, sha)?;
+    if kind != "commit" {
+        return Some(Vec::new());
+    }
+
+    let text = String::from_utf8_lossy(&body);
+    let mut parents = Vec::new();
+    for line in text.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(parent) = line.strip_prefix("parent ") {
+            parents.push(parent.trim().to_string());
+        }
+    }
+
+    Some(parents)
+}
+
+/// Collect the set of ancestor commits reachable from `start`, bounded by
+/// `limit`. Returns `None` if any object along the way is unavailable (e.g.
+/// packed), so the caller can skip the ahead/behind segment.
+fn ancestors(
+    git_dir: &std::path::Path,
+    start: &str,
+    limit: usize,
+) -> Option<std::collections::HashSet<String>> {
+    use std::collections::HashSet;
+
+    let mut seen = HashSet::new();
+    let mut stack = vec![start.to_string()];
+
+    while let Some(sha) = stack.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        if seen.len() > limit {
+            break;
+        }
+        for parent in commit_parents(git_dir, &sha)? {
+            if !seen.contains(&parent) {
+                stack.push(parent);
+            }
+        }
+    }
+
+    Some(seen)
+}
+
+/// Build a `path -> blob sha` map of the commit's tree by recursively reading
+/// tree objects.
+fn head_tree_map(git_dir: &std::path::Path) -> Option<std::collections::HashMap<String, String>> {
+    use std::collections::HashMap;
+
+    let head = resolve_head_sha(git_dir)?;
+    let (kind, body) = read_object(git_dir, &head)?;
+    if kind != "commit" {
+        return None;
+    }
+
+    let text = String::from_utf8_lossy(&body);
+    let tree = text
+        .lines()
+        .find_map(|l| l.strip_prefix("tree "))
+        .map(|s| s.trim().to_string())?;
+
+    let mut map = HashMap::new();
+    walk_tree(git_dir, &tree, "", &mut map)?;
+    Some(map)
+}
+
+fn walk_tree(
+    git_dir: &std::path::Path,
+    sha: &str,
+    prefix: &str,
+    map: &mut std::collections::HashMap<String, String>,
+) -> Option<()> {
+    let (kind, body) = read_object(git_dir, sha)?;
+    if kind != "tree" {
+        return None;
+    }
+
+    let mut i = 0;
+    while i < body.len() {
+        let sp = body[i..].iter().position(|&b| b == b' ')? + i;
+        let mode = std::str::from_utf8(&body[i..sp]).ok()?;
+        let nul = body[sp + 1..].iter().position(|&b| b == 0)? + sp + 1;
+        let name = String::from_utf8_lossy(&body[sp + 1..nul]).into_owned();
+        if nul + 21 > body.len() {
+            return None;
+        }
+        let entry_sha = hex(&body[nul + 1..nul + 21]);
+
+        let full = if prefix.is_empty() {
+            name
+        } else {
+            format!("{prefix}/{name}")
+        };
+
+        if mode == "40000" || mode == "040000" {
+            walk_tree(git_dir, &entry_sha, &full, map)?;
+        } else {
+            map.insert(full, entry_sha);
+        }
+
+        i = nul + 21;
+    }
+
+    Some(())
+}
+
+/// A `.gitignore` matcher. The supported grammar is a practical subset:
+/// comments, blank lines, `!` negation, trailing-`/` directory rules, anchored
+/// patterns (containing a slash) and `*`/`?` wildcards that do not cross a
+/// slash. Per-directory precedence is honored by cloning the accumulated rules
+/// as the walk descends.
+#[derive(Clone, Default)]
+struct Ignore {
+    rules: Vec<IgnoreRule>,
+}
+
+#[derive(Clone)]
+struct IgnoreRule {
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+    anchored: bool,
+    /// Worktree-root-relative directory the rule was read from, so anchored
+    /// patterns from a nested `.gitignore` anchor to their own directory rather
+    /// than the worktree root. Empty for the top-level `.gitignore`.
+    base: String,
+}
+
+impl Ignore {
+    /// Add the rules from a `.gitignore`, recording `base` (the file's
+    /// directory, relative to the worktree root) so anchored patterns resolve
+    /// against it.
+    fn add_file(&mut self, path: &std::path::Path, base: &str) {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            for line in content.lines() {
+                self.add_line(line, base);
+            }
+        }
+    }
+
+    fn add_line(&mut self, line: &str, base: &str) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+
+        let mut pattern = line;
+        let negate = pattern.starts_with('!');
+        if negate {
+            pattern = &pattern[1..];
+        }
+
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        self.rules.push(IgnoreRule {
+            pattern: pattern.to_string(),
+            negate,
+            dir_only,
+            anchored,
+            base: base.to_string(),
+        });
+    }
+
+    fn is_ignored(&self, rel: &str, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+
+            // Re-root the path under the directory the rule came from; a rule
+            // only applies to paths inside its own `.gitignore`'s directory.
+            let scoped = if rule.base.is_empty() {
+                Some(rel)
+            } else {
+                rel.strip_prefix(rule.base.as_str())
+                    .and_then(|r| r.strip_prefix('/'))
+            };
+            let Some(scoped) = scoped else {
+                continue;
+            };
+
+            let matched = if rule.anchored {
+                glob_match(&rule.pattern, scoped)
+            } else {
+                let base = scoped.rsplit('/').next().unwrap_or(scoped);
+                glob_match(&rule.pattern, base)
+            };
+
+            if matched {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(p: &[char], t: &[char]) -> bool {
+        if p.is_empty() {
+            return t.is_empty();
+        }
+        match p[0] {
+            '*' => {
+                if matches(&p[1..], t) {
+                    return true;
+                }
+                !t.is_empty() && t[0] != '/' && matches(p, &t[1..])
+            }
+            '?' => !t.is_empty() && t[0] != '/' && matches(&p[1..], &t[1..]),
+            c => !t.is_empty() && t[0] == c && matches(&p[1..], &t[1..]),
+        }
+    }
+
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    matches(&p, &t)
+}
+
+fn count_untracked(
+    work_tree: &std::path::Path,
+    tracked: &std::collections::HashSet<String>,
+) -> Result<usize, GitStatusError> {
+    fn rel_to(root: &std::path::Path, path: &std::path::Path) -> String {
+        path.strip_prefix(root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    /// A directory entry that should be recursed into: a real directory that is
+    /// neither `.git` nor a symlink. Symlinks are treated as plain entries (as
+    /// Git records them as blobs) so they are never followed, which also keeps
+    /// the recursion from looping on a symlink cycle.
+    fn dir_to_descend(entry: &std::fs::DirEntry) -> bool {
+        if entry.file_name().to_string_lossy() == ".git" {
+            return false;
+        }
+        matches!(entry.file_type(), Ok(ft) if ft.is_dir())
+    }
+
+    /// Whether an entirely-untracked directory holds at least one file Git
+    /// would report, honoring nested `.gitignore`s. Used to collapse such a
+    /// directory to a single `?` entry (as `git status` does) while still
+    /// skipping directories that contain only ignored files.
+    fn has_unignored_file(
+        root: &std::path::Path,
+        dir: &std::path::Path,
+        parent: &Ignore,
+    ) -> Result<bool, GitStatusError> {
+        use std::fs;
+
+        let mut ignore = parent.clone();
+        let gitignore = dir.join(".gitignore");
+        if gitignore.is_file() {
+            ignore.add_file(&gitignore, &rel_to(root, dir));
+        }
+
+        for entry in fs::read_dir(dir).map_err(GitStatusError::WalkTree)? {
+            let entry = entry.map_err(GitStatusError::WalkTree)?;
+            let path = entry.path();
+            let rel = rel_to(root, &path);
+            let is_dir = dir_to_descend(&entry);
+            if ignore.is_ignored(&rel, is_dir) {
+                continue;
+            }
+            if is_dir {
+                if has_unignored_file(root, &path, &ignore)? {
+                    return Ok(true);
+                }
+            } else if entry.file_name().to_string_lossy() != ".git" {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn walk(
+        root: &std::path::Path,
+        dir: &std::path::Path,
+        parent: &Ignore,
+        tracked: &std::collections::HashSet<String>,
+        tracked_dirs: &std::collections::HashSet<String>,
+        count: &mut usize,
+    ) -> Result<(), GitStatusError> {
+        use std::fs;
+
+        let mut ignore = parent.clone();
+        let gitignore = dir.join(".gitignore");
+        if gitignore.is_file() {
+            ignore.add_file(&gitignore, &rel_to(root, dir));
+        }
+
+        for entry in fs::read_dir(dir).map_err(GitStatusError::WalkTree)? {
+            let entry = entry.map_err(GitStatusError::WalkTree)?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == ".git" {
+                continue;
+            }
+
+            let path = entry.path();
+            let rel = rel_to(root, &path);
+            let is_dir = dir_to_descend(&entry);
+
+            if ignore.is_ignored(&rel, is_dir) {
+                continue;
+            }
+
+            if is_dir {
+                // A directory containing tracked files is descended into; one
+                // that is wholly untracked collapses to a single entry, matching
+                // how `git status` reports untracked directories.
+                if tracked_dirs.contains(rel.as_str()) {
+                    walk(root, &path, &ignore, tracked, tracked_dirs, count)?;
+                } else if has_unignored_file(root, &path, &ignore)? {
+                    *count += 1;
+                }
+            } else if !tracked.contains(rel.as_str()) {
+                *count += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Pre-compute the set of directories that contain a tracked file (at any
+    // depth) so the per-directory "does this hold tracked files?" test is a
+    // hash lookup rather than a scan of every tracked path.
+    let mut tracked_dirs: std::collections::HashSet<String> = std::collections::HashSet::new();
+    for path in tracked {
+        let mut rest = path.as_str();
+        while let Some((parent, _)) = rest.rsplit_once('/') {
+            if !tracked_dirs.insert(parent.to_string()) {
+                break;
+            }
+            rest = parent;
+        }
+    }
+
+    let mut count = 0;
+    walk(
+        work_tree,
+        work_tree,
+        &Ignore::default(),
+        tracked,
+        &tracked_dirs,
+        &mut count,
+    )?;
+    Ok(count)
+}
+
+/// Derive the working-tree status: staged (index vs `HEAD` tree), modified
+/// (index vs filesystem stat), untracked (walk honoring `.gitignore`),
+/// conflicted (unmerged index stages) and ahead/behind relative to the
+/// upstream. Object-backed counts (staged, ahead/behind) read loose or packed
+/// objects and are omitted only when the object format can't be parsed.
+fn get_git_status(
+    git_dir: &std::path::Path,
+    work_tree: &std::path::Path,
+) -> Result<GitStatus, GitStatusError> {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    let entries = read_index(git_dir)?;
+
+    let mut status = GitStatus::default();
+    let mut tracked: HashSet<String> = HashSet::new();
+    let mut conflicted: HashSet<String> = HashSet::new();
+
+    for entry in &entries {
+        tracked.insert(entry.path.clone());
+        if entry.stage != 0 {
+            conflicted.insert(entry.path.clone());
+        }
+    }
+    status.conflicted = conflicted.len();
+
+    for entry in &entries {
+        if entry.stage != 0 || conflicted.contains(&entry.path) {
+            continue;
+        }
+
+        match fs::metadata(work_tree.join(&entry.path)) {
+            Ok(meta) => {
+                if meta.size() as u32 != entry.size || meta.mtime() as u32 != entry.mtime_s {
+                    status.modified += 1;
+                }
+            }
+            Err(_) => status.modified += 1,
+        }
+    }
+
+    status.untracked = count_untracked(work_tree, &tracked)?;
+
+    if let Some(tree) = head_tree_map(git_dir) {
+        use std::collections::HashMap;
+
+        let index: HashMap<String, String> = entries
+            .iter()
+            .filter(|e| e.stage == 0)
+            .map(|e| (e.path.clone(), hex(&e.sha)))
+            .collect();
+
+        let mut staged = 0;
+        for (path, sha) in &index {
+            if tree.get(path) != Some(sha) {
+                staged += 1;
+            }
+        }
+        for path in tree.keys() {
+            if !index.contains_key(path) {
+                staged += 1;
+            }
+        }
+        status.staged = staged;
+    }
+
+    if let (Some(local), Some(upstream)) = (resolve_head_sha(git_dir), upstream_sha(git_dir)) {
+        if local == upstream {
+            status.ahead = Some(0);
+            status.behind = Some(0);
+        } else if let (Some(local_anc), Some(upstream_anc)) = (
+            ancestors(git_dir, &local, 5000),
+            ancestors(git_dir, &upstream, 5000),
+        ) {
+            status.ahead = Some(local_anc.iter().filter(|c| !upstream_anc.contains(*c)).count());
+            status.behind = Some(upstream_anc.iter().filter(|c| !local_anc.contains(*c)).count());
+        }
+    }
+
+    Ok(status)
+}
+
+/// Minimal zlib (RFC 1950/1951) inflate, enough to read loose Git objects
+/// without pulling in a dependency. Returns `None` on malformed input.
+fn inflate(input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() < 2 {
+        return None;
+    }
+
+    let mut reader = BitReader::new(&input[2..]);
+    let mut out = Vec::new();
+
+    loop {
+        let bfinal = reader.bits(1)?;
+        let btype = reader.bits(2)?;
+
+        match btype {
+            0 => {
+                reader.align();
+                let len = reader.take_u16()? as usize;
+                let _nlen = reader.take_u16()?;
+                for _ in 0..len {
+                    out.push(reader.take_byte()?);
+                }
+            }
+            1 => inflate_block(&mut reader, &mut out, &fixed_lit(), &fixed_dist())?,
+            2 => {
+                let (lit, dist) = read_dynamic(&mut reader)?;
+                inflate_block(&mut reader, &mut out, &lit, &dist)?;
+            }
+            _ => return None,
+        }
+
+        if bfinal == 1 {
+            break;
+        }
+    }
+
+    Some(out)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, byte: 0, bit: 0 }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        if self.byte >= self.data.len() {
+            return None;
+        }
+        let value = ((self.data[self.byte] >> self.bit) & 1) as u32;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+        Some(value)
+    }
+
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let mut value = 0u32;
+        for i in 0..n {
+            value |= self.bit()? << i;
+        }
+        Some(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+    }
+
+    fn take_byte(&mut self) -> Option<u8> {
+        if self.byte >= self.data.len() {
+            return None;
+        }
+        let value = self.data[self.byte];
+        self.byte += 1;
+        Some(value)
+    }
+
+    fn take_u16(&mut self) -> Option<u16> {
+        let lo = self.take_byte()? as u16;
+        let hi = self.take_byte()? as u16;
+        Some(lo | (hi << 8))
+    }
+}
+
+/// Canonical Huffman decoder built from a list of code lengths, following the
+/// construction used in zlib's reference `puff.c`.
+struct Huffman {
+    counts: [i32; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn build(lengths: &[u16]) -> Huffman {
+        let mut counts = [0i32; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+
+        let mut offsets = [0i32; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Option<u16> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for len in 1..=15 {
+            code |= reader.bit()? as i32;
+            let count = self.counts[len];
+            if code - first < count {
+                return Some(self.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+
+        None
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn fixed_lit() -> Huffman {
+    let mut lengths = [0u16; 288];
+    for (i, len) in lengths.iter_mut().enumerate() {
+        *len = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Huffman::build(&lengths)
+}
+
+fn fixed_dist() -> Huffman {
+    Huffman::build(&[5u16; 30])
+}
+
+fn read_dynamic(reader: &mut BitReader) -> Option<(Huffman, Huffman)> {
+    let hlit = reader.bits(5)? as usize + 257;
+    let hdist = reader.bits(5)? as usize + 1;
+    let hclen = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u16; 19];
+    for &slot in CODE_LENGTH_ORDER.iter().take(hclen) {
+        code_lengths[slot] = reader.bits(3)? as u16;
+    }
+    let code_huffman = Huffman::build(&code_lengths);
+
+    let mut lengths: Vec<u16> = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let sym = code_huffman.decode(reader)?;
+        match sym {
+            0..=15 => lengths.push(sym),
+            16 => {
+                let repeat = reader.bits(2)? + 3;
+                let last = *lengths.last()?;
+                for _ in 0..repeat {
+                    lengths.push(last);
+                }
+            }
+            17 => {
+                let repeat = reader.bits(3)? + 3;
+                lengths.resize(lengths.len() + repeat as usize, 0);
+            }
+            18 => {
+                let repeat = reader.bits(7)? + 11;
+                lengths.resize(lengths.len() + repeat as usize, 0);
+            }
+            _ => return None,
+        }
+    }
+
+    if lengths.len() != hlit + hdist {
+        return None;
+    }
+
+    let lit = Huffman::build(&lengths[..hlit]);
+    let dist = Huffman::build(&lengths[hlit..]);
+    Some((lit, dist))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    lit: &Huffman,
+    dist: &Huffman,
+) -> Option<()> {
+    loop {
+        let sym = lit.decode(reader)?;
+        match sym {
+            256 => return Some(()),
+            0..=255 => out.push(sym as u8),
+            257..=285 => {
+                let sym = (sym - 257) as usize;
+                let length = LENGTH_BASE[sym] as usize + reader.bits(LENGTH_EXTRA[sym])? as usize;
+                let dsym = dist.decode(reader)? as usize;
+                if dsym >= DIST_BASE.len() {
+                    return None;
+                }
+                let distance = DIST_BASE[dsym] as usize + reader.bits(DIST_EXTRA[dsym])? as usize;
+                let start = out.len().checked_sub(distance)?;
+                for i in 0..length {
+                    let byte = out[start + i];
+                    out.push(byte);
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum MainError {
+    NixShell(NotInNixShell),
+    Git(GitError),
+}
+
+impl fmt::Display for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source: &dyn std::error::Error = match self {
+            MainError::NixShell(e) => {
+                writeln!(f, "failed to get nix info")?;
+                e
+            },
+            MainError::Git(e) => {
+                writeln!(f, "failed to get git info")?;
+                e
+            },
+        };
+
+        writeln!(f, "Caused by:")?;
+
+        let mut source = Some(source);
+        while let Some(err) = source {
+            writeln!(f, "{err}")?;
+            source = err.source();
+        }
+
+        Ok(())
+    }
+}
+
+/// A prompt segment that can be computed independently on its own thread.
+/// `index` fixes the left-to-right position so results can be reassembled in a
+/// stable order regardless of which thread finishes first.
+trait Segment: Send {
+    fn index(&self) -> usize;
+    fn name(&self) -> &'static str;
+    fn render(&self, ctx: &Context, config: &Config) -> Result<DecoratedString, MainError>;
+}
+
+struct CwdSegment;
+impl Segment for CwdSegment {
+    fn index(&self) -> usize {
+        0
+    }
+    fn name(&self) -> &'static str {
+        "cwd"
+    }
+    fn render(&self, ctx: &Context, config: &Config) -> Result<DecoratedString, MainError> {
+        Ok(get_cwd(ctx, config))
+    }
+}
+
+struct GitSegment;
+impl Segment for GitSegment {
+    fn index(&self) -> usize {
+        1
+    }
+    fn name(&self) -> &'static str {
+        "git"
+    }
+    fn render(&self, ctx: &Context, config: &Config) -> Result<DecoratedString, MainError> {
+        get_git_info(ctx, config).map_err(MainError::Git)
+    }
+}
+
+struct NixSegment;
+impl Segment for NixSegment {
+    fn index(&self) -> usize {
+        2
+    }
+    fn name(&self) -> &'static str {
+        "nix"
+    }
+    fn render(&self, ctx: &Context, config: &Config) -> Result<DecoratedString, MainError> {
+        get_nix_shell(ctx, config).map_err(MainError::NixShell)
+    }
+}
+
+fn main() {
+    // This program uses these environment variables:
+    //
+    // 1. `PROMPT_DEBUG`:
+    //      1 => Print out debug stats
+    //      0 => No debug
+    // 2. `PROMPT_SHELL_TYPE`:
+    //      'bash' => The current shell is bash
+    //      'zsh' => The current shell is zsh
+    //
+    // Here is how to setup the prompt for zsh:
+    // ```.zshrc
+    // PROMPT="$(PROMPT_SHELL_TYPE='zsh' ./path/to/prompt/binary)"
+    // ```
+
+    use std::sync::{mpsc, Arc};
+    use std::time::{Duration, Instant};
+
+    let ctx = Arc::new(Context::new());
+
+    let escape_ansi = |s: &str| -> String {
+        match ctx.shell_type {
+            ShellType::Zsh => format!("%{{{s}%}}"),
+            ShellType::Bash => format!("\\[{s}\\]"),
+            ShellType::Unknown => s.to_string(),
+        }
+    };
+
+    let config = Arc::new(Config::load(&ctx));
+
+    let segments: Vec<Box<dyn Segment + 'static>> =
+        vec![Box::new(CwdSegment), Box::new(GitSegment), Box::new(NixSegment)];
+    let count = segments.len();
+
+    // Names in index order so timed-out segments can be reported by name even
+    // though their threads never delivered a result.
+    let mut names = vec![""; count];
+    for segment in &segments {
+        names[segment.index()] = segment.name();
+    }
+
+    // Each segment runs on its own thread so a slow one (e.g. a git walk over a
+    // deep tree) can't stall the others. Results are gathered over a channel up
+    // to a deadline; stragglers are simply never collected.
+    let timeout = ctx
+        .var("PROMPT_TIMEOUT_MS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(100);
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+
+    let (tx, rx) = mpsc::channel();
+    for segment in segments {
+        let ctx = Arc::clone(&ctx);
+        let config = Arc::clone(&config);
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let result = segment.render(&ctx, &config);
+            let _ = tx.send((segment.index(), result));
+        });
+    }
+    drop(tx);
+
+    let mut slots: Vec<Option<Result<DecoratedString, MainError>>> =
+        (0..count).map(|_| None).collect();
+    let mut received = 0;
+    while received < count {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok((index, result)) => {
+                if index < slots.len() {
+                    slots[index] = Some(result);
+                }
+                received += 1;
+            }
+            Err(_) => break,
+        }
+    }
+
+    let debug = ctx.var("PROMPT_DEBUG") == Some("1");
+
+    let mut components = Vec::with_capacity(count);
+    for (index, slot) in slots.into_iter().enumerate() {
+        match slot {
+            Some(Ok(component)) => components.push(component),
+            Some(Err(error)) => {
+                if debug {
+                    eprintln!("{error}");
+                }
+            }
+            None => {
+                if debug {
+                    eprintln!("segment `{}` timed out after {timeout}ms", names[index]);
+                }
+            }
+        }
+    }
+
+    let joined = components
+        .into_iter()
+        .map(|s| format!("{} ", s.to_ansi(&escape_ansi)))
+        .collect::<Vec<_>>()
+        .join("");
 
     print!(" {joined}-> ");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn ctx(vars: &[(&str, &str)], shell: ShellType) -> Context {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Context::test(vars, PathBuf::from("/"), shell)
+    }
+
+    #[test]
+    fn cwd_shortens_intermediate_components_and_collapses_home() {
+        let c = ctx(
+            &[("PWD", "/home/nils/dev/prompt"), ("HOME", "/home/nils")],
+            ShellType::Unknown,
+        );
+        assert_eq!(get_cwd(&c, &Config::default()).plain(), "~/d/prompt");
+    }
+
+    #[test]
+    fn cwd_without_pwd_is_a_loud_marker() {
+        let c = ctx(&[], ShellType::Unknown);
+        assert_eq!(get_cwd(&c, &Config::default()).plain(), "!!!");
+    }
+
+    #[test]
+    fn nix_pure_shell_is_labelled_pure() {
+        let c = ctx(&[("IN_NIX_SHELL", "pure")], ShellType::Unknown);
+        assert_eq!(
+            get_nix_shell(&c, &Config::default()).unwrap().plain(),
+            "(nix: pure)"
+        );
+    }
+
+    #[test]
+    fn nix_impure_shell_reports_name_when_set() {
+        let c = ctx(
+            &[("IN_NIX_SHELL", "impure"), ("name", "hello-dev")],
+            ShellType::Unknown,
+        );
+        assert_eq!(
+            get_nix_shell(&c, &Config::default()).unwrap().plain(),
+            "(nix: impure · hello-dev)"
+        );
+    }
+
+    #[test]
+    fn not_in_any_nix_shell() {
+        let c = ctx(&[("PATH", "/usr/bin:/bin")], ShellType::Unknown);
+        assert!(NixShellType::detect_shell_type(&c).is_err());
+    }
+
+    #[test]
+    fn bare_name_var_does_not_imply_a_dev_shell() {
+        // A `/nix/store` `PATH` plus a generic `name` must NOT be classified as
+        // `nix develop` — that requires a Nix-specific build-env marker.
+        let c = ctx(
+            &[("PATH", "/nix/store/abc-bash/bin:/usr/bin"), ("name", "foo")],
+            ShellType::Unknown,
+        );
+        assert!(matches!(
+            NixShellType::detect_shell_type(&c),
+            Ok(NixShellType::Unknown)
+        ));
+    }
+
+    #[test]
+    fn dev_shell_detected_via_build_env_marker() {
+        let c = ctx(
+            &[
+                ("PATH", "/nix/store/abc-bash/bin:/usr/bin"),
+                ("buildInputs", "/nix/store/xyz-hello"),
+                ("name", "hello-dev"),
+            ],
+            ShellType::Unknown,
+        );
+        assert!(matches!(
+            NixShellType::detect_shell_type(&c),
+            Ok(NixShellType::Develop)
+        ));
+    }
+
+    #[test]
+    fn short_hash_is_char_boundary_safe() {
+        assert_eq!(short_hash("abcdef012345"), "abcde..");
+        assert_eq!(short_hash("abc"), "abc");
+        assert_eq!(short_hash(""), "");
+    }
+
+    #[test]
+    fn glob_matches_do_not_cross_slashes() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(!glob_match("*.rs", "src/main.rs"));
+        assert!(glob_match("foo?", "foot"));
+    }
+
+    #[test]
+    fn nested_gitignore_rule_anchors_to_its_own_directory() {
+        let mut ignore = Ignore::default();
+        ignore.add_line("/build/", "src");
+        // Anchored to `src`, so it ignores `src/build` but not a top-level
+        // `build` or an unrelated `lib/build`.
+        assert!(ignore.is_ignored("src/build", true));
+        assert!(!ignore.is_ignored("build", true));
+        assert!(!ignore.is_ignored("lib/build", true));
+    }
+
+    #[test]
+    fn object_id_bytes_decodes_hex() {
+        assert_eq!(object_id_bytes("00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(object_id_bytes("0"), None);
+        assert_eq!(object_id_bytes("zz"), None);
+    }
+}